@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// A single entry returned from `FileSystem::read_dir`.
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// Mirrors the subset of `std::fs::Metadata` used by this crate.
+#[derive(Clone)]
+pub struct Metadata {
+    readonly: bool,
+    modified: Option<SystemTime>,
+}
+
+impl Metadata {
+    pub fn permissions(&self) -> Permissions {
+        Permissions { readonly: self.readonly, mode: None }
+    }
+
+    /// The last-modified time, used to skip regenerating outputs that
+    /// are already up to date. `None` if it could not be determined.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+}
+
+/// Mirrors the subset of `std::fs::Permissions` used by this crate, plus
+/// an optional Unix mode for callers that want more than the read-only
+/// bit (see `OutputPermissions::Mode`).
+#[derive(Clone)]
+pub struct Permissions {
+    readonly: bool,
+    mode: Option<u32>,
+}
+
+impl Permissions {
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// Also derives `readonly` from the mode's owner write bit, so that
+    /// `InMemoryFileSystem` (which stores the two fields independently)
+    /// reports the same `readonly()` a real filesystem would after
+    /// `chmod`ing to this mode.
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = Some(mode);
+        self.readonly = mode & 0o200 == 0;
+    }
+}
+
+/// Abstracts over the filesystem operations needed to walk a directory,
+/// read input files and write generated output, so that `Processor`s can
+/// be exercised without touching the real disk.
+pub trait FileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write>>;
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+}
+
+/// The default `FileSystem`, delegating every operation to `std::fs`.
+pub struct StdFileSystem;
+
+impl FileSystem for StdFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = vec![];
+        for entry in try!(fs::read_dir(path)) {
+            let entry = try!(entry);
+            let file_type = try!(entry.file_type());
+            entries.push(DirEntry { path: entry.path(), is_dir: file_type.is_dir() });
+        }
+        Ok(entries)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        use std::io::Read;
+        let mut file = try!(fs::File::open(path));
+        let mut contents = String::new();
+        try!(file.read_to_string(&mut contents));
+        Ok(contents)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+        Ok(Box::new(try!(fs::File::create(path))))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = try!(fs::metadata(path));
+        Ok(Metadata { readonly: metadata.permissions().readonly(), modified: metadata.modified().ok() })
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+        let mut std_permissions = try!(fs::metadata(path)).permissions();
+        std_permissions.set_readonly(permissions.readonly());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = permissions.mode() {
+                std_permissions.set_mode(mode);
+            }
+        }
+
+        fs::set_permissions(path, std_permissions)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+/// An in-memory `FileSystem`, backed by a `HashMap<PathBuf, Vec<u8>>`, so
+/// crate authors can feed synthetic inputs to a `Processor` and assert on
+/// the generated bytes without touching the real disk. Built on
+/// `Mutex`es rather than `RefCell`s so that it is `Sync` and can back the
+/// parallel processing path. The public `process_*` entry points and
+/// `ProcessDirBuilder` always use `StdFileSystem` and take no other
+/// `FileSystem`.
+pub struct InMemoryFileSystem {
+    files: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+    permissions: Mutex<HashMap<PathBuf, Permissions>>,
+    modified: Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
+}
+
+impl InMemoryFileSystem {
+    pub fn new() -> Self {
+        InMemoryFileSystem {
+            files: Arc::new(Mutex::new(HashMap::new())),
+            permissions: Mutex::new(HashMap::new()),
+            modified: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Seeds the filesystem with an input file, as if it had already been
+    /// written to disk.
+    pub fn insert<P: Into<PathBuf>, C: Into<Vec<u8>>>(&self, path: P, contents: C) {
+        let path = path.into();
+        self.files.lock().unwrap().insert(path.clone(), contents.into());
+        self.modified.lock().unwrap().insert(path, SystemTime::now());
+    }
+
+    /// Returns the current contents of `path`, if any file was written
+    /// there, either via `insert` or by a `Processor`.
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        self.files.lock().unwrap().get(path).cloned()
+    }
+
+    /// Returns the permissions last set on `path` via `FileSystem::set_permissions`.
+    pub fn permissions(&self, path: &Path) -> Option<Permissions> {
+        self.permissions.lock().unwrap().get(path).cloned()
+    }
+
+    /// Overrides the last-modified time recorded for `path`, so tests can
+    /// exercise the up-to-date check deterministically instead of relying
+    /// on the wall clock.
+    pub fn set_modified(&self, path: &Path, modified: SystemTime) {
+        self.modified.lock().unwrap().insert(path.to_path_buf(), modified);
+    }
+}
+
+impl FileSystem for InMemoryFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut dirs = HashMap::new();
+        let mut entries = vec![];
+        let mut seen = HashMap::new();
+
+        for file_path in self.files.lock().unwrap().keys() {
+            if let Ok(rest) = file_path.strip_prefix(path) {
+                let mut components = rest.components();
+                if let Some(first) = components.next() {
+                    let child = path.join(first.as_os_str());
+                    let is_dir = components.next().is_some();
+                    if is_dir {
+                        dirs.insert(child, true);
+                    } else if !seen.contains_key(&child) {
+                        seen.insert(child.clone(), true);
+                        entries.push(DirEntry { path: child, is_dir: false });
+                    }
+                }
+            }
+        }
+
+        for (dir, _) in dirs {
+            entries.push(DirEntry { path: dir, is_dir: true });
+        }
+
+        Ok(entries)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        match self.files.lock().unwrap().get(path) {
+            Some(bytes) => {
+                String::from_utf8(bytes.clone())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+        self.files.lock().unwrap().insert(path.to_path_buf(), vec![]);
+        self.modified.lock().unwrap().insert(path.to_path_buf(), SystemTime::now());
+        Ok(Box::new(InMemoryWriter { path: path.to_path_buf(), buffer: vec![], files: self.files.clone(), modified: self.modified.clone() }))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        if self.files.lock().unwrap().contains_key(path) {
+            let readonly = self.permissions.lock().unwrap().get(path).map_or(false, |p| p.readonly());
+            let modified = self.modified.lock().unwrap().get(path).cloned();
+            Ok(Metadata { readonly: readonly, modified: modified })
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound, "file not found"))
+        }
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: Permissions) -> io::Result<()> {
+        self.permissions.lock().unwrap().insert(path.to_path_buf(), permissions);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+        }
+    }
+}
+
+struct InMemoryWriter {
+    path: PathBuf,
+    buffer: Vec<u8>,
+    files: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+    modified: Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
+}
+
+impl Write for InMemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.lock().unwrap().insert(self.path.clone(), self.buffer.clone());
+        self.modified.lock().unwrap().insert(self.path.clone(), SystemTime::now());
+        Ok(())
+    }
+}
+
+impl Drop for InMemoryWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
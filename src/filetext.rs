@@ -0,0 +1,64 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use filesystem::FileSystem;
+use {io_error, Error, Span};
+
+/// The full contents of a source file, along with its path, so that
+/// errors can be reported with line/column information and a
+/// highlighted excerpt.
+pub struct FileText {
+    path: PathBuf,
+    contents: String,
+}
+
+impl FileText {
+    pub fn from_path<P: AsRef<Path>>(path: P, fs: &impl FileSystem) -> Result<FileText, Error> {
+        let path = path.as_ref().to_path_buf();
+        let contents = try!(fs.read_to_string(&path).map_err(|e| io_error("reading", &path, e)));
+        Ok(FileText { path: path, contents: contents })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contents(&self) -> &str {
+        &self.contents
+    }
+
+    /// Converts a byte offset into the file into a zero-indexed
+    /// (line, column) pair.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let mut line = 0;
+        let mut col = 0;
+
+        for (index, ch) in self.contents.char_indices() {
+            if index >= offset {
+                break;
+            }
+
+            if ch == '\n' {
+                line += 1;
+                col = 0;
+            } else {
+                col += 1;
+            }
+        }
+
+        (line, col)
+    }
+
+    /// Prints the line on which `span` starts, with a single `^` under the
+    /// span's first character. Later lines of a multi-line span are not
+    /// printed, and the rest of the span is not underlined.
+    pub fn highlight<O: Write>(&self, span: Span, out: &mut O) -> io::Result<()> {
+        let (start_line, start_col) = self.line_col(span.0);
+
+        let line = self.contents.lines().nth(start_line).unwrap_or("");
+        try!(writeln!(out, "{}", line));
+        try!(writeln!(out, "{}^", " ".repeat(start_col)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+/// Tracks which directory entries a walk should skip: dotfiles (by
+/// default) plus any `.gitignore`-style patterns picked up along the way
+/// or added explicitly through `ProcessDirBuilder`.
+#[derive(Clone)]
+pub struct IgnoreMatcher {
+    patterns: Vec<String>,
+    ignore_hidden: bool,
+}
+
+impl IgnoreMatcher {
+    pub fn new() -> IgnoreMatcher {
+        IgnoreMatcher { patterns: vec![], ignore_hidden: true }
+    }
+
+    pub fn ignore_hidden(&mut self, ignore_hidden: bool) {
+        self.ignore_hidden = ignore_hidden;
+    }
+
+    pub fn add_pattern<S: Into<String>>(&mut self, pattern: S) {
+        self.patterns.push(pattern.into());
+    }
+
+    /// Adds every pattern found in a `.gitignore` file's contents,
+    /// skipping blank lines and `#` comments.
+    pub fn add_gitignore(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = line.trim_end_matches('/');
+            // A leading `/` is dropped, so the pattern is not anchored:
+            // the matcher is handed down the walk into subdirectories, and
+            // the pattern also skips same-named entries in every directory
+            // below the one the `.gitignore` was read from.
+            let line = line.trim_start_matches('/');
+            self.patterns.push(line.to_string());
+        }
+    }
+
+    /// Whether a directory entry with this file name should be skipped.
+    /// Only matches against the entry's own name, mirroring how a single
+    /// `.gitignore` line matches entries within its own directory.
+    pub fn is_ignored(&self, file_name: &str) -> bool {
+        if self.ignore_hidden && file_name.starts_with('.') {
+            return true;
+        }
+        self.patterns.iter().any(|pattern| glob_match(pattern, file_name))
+    }
+}
+
+// A dynamic-programming table rather than naive recursive backtracking:
+// the latter is exponential on adversarial patterns (many consecutive
+// `*`s against a non-matching string), and this runs on every directory
+// entry of every walked directory.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+
+    // matches[j] tracks whether the pattern prefix processed so far
+    // matches the text prefix of length j.
+    let mut matches = vec![false; text.len() + 1];
+    matches[0] = true;
+
+    for &p in pattern {
+        if p == b'*' {
+            // A `*` matching zero-or-more carries forward any prefix
+            // match already found at or before this point in the text.
+            for j in 1..matches.len() {
+                matches[j] = matches[j] || matches[j - 1];
+            }
+        } else {
+            for j in (1..matches.len()).rev() {
+                matches[j] = matches[j - 1] && (p == b'?' || p == text[j - 1]);
+            }
+            matches[0] = false;
+        }
+    }
+
+    matches[text.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IgnoreMatcher;
+
+    #[test]
+    fn anchored_pattern_matches_bare_entry_name() {
+        let mut ignore = IgnoreMatcher::new();
+        // The standard `cargo new` .gitignore: `/target`.
+        ignore.add_gitignore("/target\n");
+        assert!(ignore.is_ignored("target"));
+    }
+
+    #[test]
+    fn unanchored_pattern_still_matches() {
+        let mut ignore = IgnoreMatcher::new();
+        ignore.add_gitignore("*.log\n");
+        assert!(ignore.is_ignored("debug.log"));
+        assert!(!ignore.is_ignored("target"));
+    }
+
+    #[test]
+    fn many_consecutive_wildcards_match_without_backtracking() {
+        let mut ignore = IgnoreMatcher::new();
+        // Naive recursive backtracking is exponential on a pattern like
+        // this matched against a string that doesn't satisfy it; the
+        // DP-based matcher does pattern length times text length work.
+        ignore.add_pattern("*".repeat(40) + "x");
+        assert!(!ignore.is_ignored(&"a".repeat(40)));
+    }
+}
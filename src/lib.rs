@@ -1,41 +1,171 @@
+mod filesystem;
 mod filetext;
+mod ignore;
 
 use std::env::current_dir;
-use std::fs;
+use std::fmt;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::thread;
 
+pub use filesystem::{DirEntry, FileSystem, InMemoryFileSystem, Metadata, Permissions, StdFileSystem};
 pub use filetext::FileText;
+pub use ignore::IgnoreMatcher;
 
 #[derive(Clone, Copy)]
 pub struct Span(pub usize, pub usize);
 
 pub enum Error {
     Source(FileText, String, Span),
-    Io(io::Error)
+    Io(IoError)
+}
+
+/// An `io::Error` decorated with the path and operation that caused it,
+/// so `perform_processing_or_die` can report e.g. `error creating
+/// /path/foo.rs: permission denied` instead of a bare OS error.
+pub struct IoError {
+    path: PathBuf,
+    operation: &'static str,
+    source: io::Error,
+}
+
+impl IoError {
+    fn new(operation: &'static str, path: &Path, source: io::Error) -> IoError {
+        IoError { path: path.to_path_buf(), operation: operation, source: source }
+    }
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.as_os_str().is_empty() {
+            write!(f, "{}", self.source)
+        } else {
+            write!(f, "error {} {}: {}", self.operation, self.path.display(), self.source)
+        }
+    }
 }
 
 impl From<io::Error> for Error {
     fn from(from: io::Error) -> Self {
-        Error::Io(from)
+        Error::Io(IoError { path: PathBuf::new(), operation: "processing", source: from })
     }
 }
 
+pub(crate) fn io_error(operation: &'static str, path: &Path, source: io::Error) -> Error {
+    Error::Io(IoError::new(operation, path, source))
+}
+
+/// Controls what permissions a generated `.rs` file is left with once
+/// processing finishes.
+pub enum OutputPermissions {
+    /// Leave the file with whatever permissions it was created with.
+    Writable,
+    /// Mark the file read-only via the read-only bit, on every platform.
+    ReadOnly,
+    /// Set an explicit Unix mode, e.g. `0o444` or `0o755` to preserve an
+    /// executable bit. Falls back to the read-only bit on non-Unix
+    /// platforms, since there is no mode to set there.
+    Mode(u32),
+}
+
 pub trait Processor {
     fn process<O: Write>(&self, input: FileText, output: &mut O) -> Result<(), Error>;
 }
 
 pub fn process_root<T: Processor>(extension: &str, processor: &T) {
-    perform_processing_or_die(&current_dir().expect("cannot determin current directory"), extension, processor)
+    perform_processing_or_die(&current_dir().expect("cannot determin current directory"), extension, processor, &StdFileSystem, &IgnoreMatcher::new(), &OutputPermissions::ReadOnly, true)
 }
 
 pub fn process_dir<T: Processor, P: AsRef<Path>>(path: P, extension: &str, processor: &T) {
-    perform_processing_or_die(&path.as_ref(), extension, processor)
+    perform_processing_or_die(&path.as_ref(), extension, processor, &StdFileSystem, &IgnoreMatcher::new(), &OutputPermissions::ReadOnly, true)
+}
+
+/// Like `process_root`, but dispatches the per-file work across a thread
+/// pool sized to the available parallelism instead of processing files
+/// one at a time.
+pub fn process_root_parallel<T: Processor + Sync + Send>(extension: &str, processor: &T) {
+    perform_processing_or_die_parallel(&current_dir().expect("cannot determin current directory"), extension, processor, &StdFileSystem, &IgnoreMatcher::new(), &OutputPermissions::ReadOnly, true)
+}
+
+/// Like `process_dir`, but dispatches the per-file work across a thread
+/// pool sized to the available parallelism instead of processing files
+/// one at a time.
+pub fn process_dir_parallel<T: Processor + Sync + Send, P: AsRef<Path>>(path: P, extension: &str, processor: &T) {
+    perform_processing_or_die_parallel(&path.as_ref(), extension, processor, &StdFileSystem, &IgnoreMatcher::new(), &OutputPermissions::ReadOnly, true)
 }
 
-fn perform_processing_or_die<T: Processor>(root_dir: &Path, extension: &str, processor: &T) {
-    match perform_processing(root_dir, extension, processor) {
+/// Starts building a customized `process_dir` run, letting callers tweak
+/// which directories the walk skips before running it with `.run(...)`
+/// or, for the thread-pool variant, `.run_parallel(...)`.
+pub fn process_dir_builder<P: AsRef<Path>>(path: P) -> ProcessDirBuilder {
+    ProcessDirBuilder {
+        path: path.as_ref().to_path_buf(),
+        ignore: IgnoreMatcher::new(),
+        output_permissions: OutputPermissions::ReadOnly,
+        check_up_to_date: true,
+    }
+}
+
+/// A builder for `process_dir` runs that need to customize which
+/// directories the walker skips, e.g. `.ignore_hidden(false)` or
+/// `.add_ignore_pattern("vendor")`, what permissions generated files are
+/// left with via `.output_permissions(...)`, or whether up-to-date
+/// outputs are skipped via `.check_up_to_date(...)`.
+pub struct ProcessDirBuilder {
+    path: PathBuf,
+    ignore: IgnoreMatcher,
+    output_permissions: OutputPermissions,
+    check_up_to_date: bool,
+}
+
+impl ProcessDirBuilder {
+    pub fn ignore_hidden(mut self, ignore_hidden: bool) -> Self {
+        self.ignore.ignore_hidden(ignore_hidden);
+        self
+    }
+
+    pub fn add_ignore_pattern<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.ignore.add_pattern(pattern);
+        self
+    }
+
+    pub fn output_permissions(mut self, output_permissions: OutputPermissions) -> Self {
+        self.output_permissions = output_permissions;
+        self
+    }
+
+    /// Whether a file whose existing output is newer than its input
+    /// should be left alone instead of reprocessed. Defaults to `true`;
+    /// pass `false` to always force regeneration.
+    pub fn check_up_to_date(mut self, check_up_to_date: bool) -> Self {
+        self.check_up_to_date = check_up_to_date;
+        self
+    }
+
+    pub fn run<T: Processor>(self, extension: &str, processor: &T) {
+        perform_processing_or_die(&self.path, extension, processor, &StdFileSystem, &self.ignore, &self.output_permissions, self.check_up_to_date)
+    }
+
+    /// Like `.run(...)`, but dispatches the per-file work across a thread
+    /// pool sized to the available parallelism instead of processing
+    /// files one at a time. This is the way to get a force-regenerating
+    /// parallel run, e.g. `.check_up_to_date(false).run_parallel(...)`.
+    pub fn run_parallel<T: Processor + Sync + Send>(self, extension: &str, processor: &T) {
+        perform_processing_or_die_parallel(&self.path, extension, processor, &StdFileSystem, &self.ignore, &self.output_permissions, self.check_up_to_date)
+    }
+}
+
+fn perform_processing_or_die<T: Processor>(root_dir: &Path, extension: &str, processor: &T, fs: &impl FileSystem, ignore: &IgnoreMatcher, output_permissions: &OutputPermissions, check_up_to_date: bool) {
+    die_on_error(perform_processing(root_dir, extension, processor, fs, ignore, output_permissions, check_up_to_date))
+}
+
+fn perform_processing_or_die_parallel<T: Processor + Sync + Send>(root_dir: &Path, extension: &str, processor: &T, fs: &(impl FileSystem + Sync), ignore: &IgnoreMatcher, output_permissions: &OutputPermissions, check_up_to_date: bool) {
+    die_on_error(perform_processing_parallel(root_dir, extension, processor, fs, ignore, output_permissions, check_up_to_date))
+}
+
+fn die_on_error(result: Result<(), Error>) {
+    match result {
         Ok(()) => (),
         Err(error) => {
             match error {
@@ -61,65 +191,344 @@ fn perform_processing_or_die<T: Processor>(root_dir: &Path, extension: &str, pro
     }
 }
 
-fn perform_processing<T: Processor>(root_dir: &Path, extension: &str, processor: &T) -> Result<(), Error> {
-    let files = try!(files(root_dir, extension));
-    for file in files {
-        let rs_file = file.with_extension("rs");
+fn perform_processing<T: Processor>(root_dir: &Path, extension: &str, processor: &T, fs: &impl FileSystem, ignore: &IgnoreMatcher, output_permissions: &OutputPermissions, check_up_to_date: bool) -> Result<(), Error> {
+    let files = try!(files(root_dir, extension, fs, ignore));
+    for file in &files {
+        // FIXME: should probably not unwrap here
+        println!("cargo:rerun-if-changed={}", file.to_str().unwrap());
 
+        try!(process_one_file(file, processor, fs, output_permissions, check_up_to_date));
+    }
+    Ok(())
+}
+
+/// Same as `perform_processing`, but reads, processes and writes each
+/// file on a worker thread. Every file produces an independent output,
+/// so the only shared state is reporting: the `cargo:rerun-if-changed`
+/// lines are all emitted up front, and of any failing files the one
+/// with the lowest index in the walk order is reported.
+fn perform_processing_parallel<T: Processor + Sync + Send>(root_dir: &Path, extension: &str, processor: &T, fs: &(impl FileSystem + Sync), ignore: &IgnoreMatcher, output_permissions: &OutputPermissions, check_up_to_date: bool) -> Result<(), Error> {
+    let files = try!(files(root_dir, extension, fs, ignore));
+    for file in &files {
         // FIXME: should probably not unwrap here
         println!("cargo:rerun-if-changed={}", file.to_str().unwrap());
+    }
 
-        try!(remove_old_file(&rs_file));
+    let worker_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).max(1);
+    let chunk_size = (files.len() + worker_count - 1) / worker_count;
+    if chunk_size == 0 {
+        return Ok(());
+    }
 
-        let input_file = try!(FileText::from_path(file));
-        let mut output_file = try!(fs::File::create(&rs_file));
+    let results: Vec<Result<(), Error>> = thread::scope(|scope| {
+        let handles: Vec<_> = files.chunks(chunk_size).map(|chunk| {
+            scope.spawn(move || -> Vec<Result<(), Error>> {
+                chunk.iter().map(|file| process_one_file(file, processor, fs, output_permissions, check_up_to_date)).collect()
+            })
+        }).collect();
 
-        try!(processor.process(input_file, &mut output_file));
+        handles.into_iter()
+            .flat_map(|handle| handle.join().expect("worker thread panicked"))
+            .collect()
+    });
 
-        try!(make_read_only(&rs_file));
+    for result in results {
+        try!(result);
     }
     Ok(())
 }
 
-fn remove_old_file(rs_file: &Path) -> io::Result<()> {
-    match fs::remove_file(rs_file) {
+fn process_one_file<T: Processor>(file: &Path, processor: &T, fs: &impl FileSystem, output_permissions: &OutputPermissions, check_up_to_date: bool) -> Result<(), Error> {
+    let rs_file = file.with_extension("rs");
+
+    if check_up_to_date && is_up_to_date(file, &rs_file, fs) {
+        return Ok(());
+    }
+
+    try!(remove_old_file(&rs_file, fs));
+
+    let input_file = try!(FileText::from_path(file, fs));
+    let mut output_file = try!(fs.create(&rs_file).map_err(|e| io_error("creating", &rs_file, e)));
+
+    try!(processor.process(input_file, &mut output_file));
+
+    apply_output_permissions(&rs_file, fs, output_permissions)
+}
+
+/// Whether `rs_file` can be left alone because it was generated after
+/// `file` was last modified. Conservative: a missing output, a missing
+/// input, or equal/unorderable timestamps (clock skew) all count as
+/// "not up to date" and fall through to regeneration.
+fn is_up_to_date(file: &Path, rs_file: &Path, fs: &impl FileSystem) -> bool {
+    let input_modified = fs.metadata(file).ok().and_then(|m| m.modified());
+    let output_modified = fs.metadata(rs_file).ok().and_then(|m| m.modified());
+
+    match (input_modified, output_modified) {
+        (Some(input_modified), Some(output_modified)) => output_modified > input_modified,
+        _ => false,
+    }
+}
+
+fn remove_old_file(rs_file: &Path, fs: &impl FileSystem) -> Result<(), Error> {
+    // Previously-generated output may still carry the read-only bit we set
+    // in `apply_output_permissions`; clear it before removing so a stale
+    // but otherwise-removable file doesn't surface as `PermissionDenied`.
+    if let Ok(metadata) = fs.metadata(rs_file) {
+        let mut permissions = metadata.permissions();
+        if permissions.readonly() {
+            permissions.set_readonly(false);
+            try!(fs.set_permissions(rs_file, permissions).map_err(|e| io_error("setting permissions on", rs_file, e)));
+        }
+    }
+
+    match fs.remove_file(rs_file) {
         Ok(()) => Ok(()),
         Err(e) => {
-            // Unix reports NotFound, Windows PermissionDenied!
             match e.kind() {
-                io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied=> Ok(()),
-                _ => Err(e),
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(io_error("removing", rs_file, e)),
             }
         }
     }
 }
 
-fn make_read_only(rs_file: &Path) -> io::Result<()> {
-    let rs_metadata = try!(fs::metadata(&rs_file));
+fn apply_output_permissions(rs_file: &Path, fs: &impl FileSystem, output_permissions: &OutputPermissions) -> Result<(), Error> {
+    if let OutputPermissions::Writable = *output_permissions {
+        return Ok(());
+    }
+
+    let rs_metadata = try!(fs.metadata(rs_file).map_err(|e| io_error("reading metadata for", rs_file, e)));
     let mut rs_permissions = rs_metadata.permissions();
-    rs_permissions.set_readonly(true);
-    fs::set_permissions(&rs_file, rs_permissions)
+
+    match *output_permissions {
+        OutputPermissions::Writable => unreachable!(),
+        OutputPermissions::ReadOnly => rs_permissions.set_readonly(true),
+        OutputPermissions::Mode(mode) => {
+            if cfg!(unix) {
+                rs_permissions.set_mode(mode);
+            } else {
+                rs_permissions.set_readonly(true);
+            }
+        },
+    }
+
+    fs.set_permissions(rs_file, rs_permissions).map_err(|e| io_error("setting permissions on", rs_file, e))
 }
 
-fn files<P:AsRef<Path>>(root_dir: P, extension: &str) -> io::Result<Vec<PathBuf>> {
+fn files<P: AsRef<Path>>(root_dir: P, extension: &str, fs: &impl FileSystem, ignore: &IgnoreMatcher) -> Result<Vec<PathBuf>, Error> {
+    let root_dir = root_dir.as_ref();
     let mut result = vec![];
-    for entry in try!(fs::read_dir(root_dir)) {
-        let entry = try!(entry);
-        let file_type = try!(entry.file_type());
 
-        let path = entry.path();
+    let mut ignore = ignore.clone();
+    if let Ok(contents) = fs.read_to_string(&root_dir.join(".gitignore")) {
+        ignore.add_gitignore(&contents);
+    }
+
+    let entries = try!(fs.read_dir(root_dir).map_err(|e| io_error("reading directory", root_dir, e)));
+    for entry in entries {
+        let file_name = match entry.path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+
+        if ignore.is_ignored(file_name) {
+            continue;
+        }
 
-        if file_type.is_dir() {
-            result.extend(try!(files(&path, extension)));
+        if entry.is_dir {
+            result.extend(try!(files(&entry.path, extension, fs, &ignore)));
         }
 
         if
-            file_type.is_file() &&
-            path.extension().is_some() &&
-            path.extension().unwrap() == extension
+            !entry.is_dir &&
+            entry.path.extension().is_some() &&
+            entry.path.extension().unwrap() == extension
         {
-            result.push(path);
+            result.push(entry.path);
         }
     }
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use {apply_output_permissions, io_error, is_up_to_date, perform_processing, perform_processing_parallel};
+    use {Error, FileSystem, FileText, InMemoryFileSystem, IgnoreMatcher, OutputPermissions, Processor, StdFileSystem};
+
+    struct UppercaseProcessor;
+
+    impl Processor for UppercaseProcessor {
+        fn process<O: ::std::io::Write>(&self, input: FileText, output: &mut O) -> Result<(), Error> {
+            output.write_all(input.contents().to_uppercase().as_bytes()).map_err(|e| io_error("writing", input.path(), e))
+        }
+    }
+
+    #[test]
+    fn skips_directories_matched_by_gitignore() {
+        let fs = InMemoryFileSystem::new();
+        fs.insert("src/.gitignore", "/target\n");
+        fs.insert("src/lib.in", "hello");
+        fs.insert("src/target/ignored.in", "stale");
+
+        perform_processing("src".as_ref(), "in", &UppercaseProcessor, &fs, &IgnoreMatcher::new(), &OutputPermissions::Writable, false).ok().expect("processing failed");
+
+        assert_eq!(fs.contents("src/lib.rs".as_ref()).unwrap(), b"HELLO");
+        assert!(fs.contents("src/target/ignored.rs".as_ref()).is_none());
+    }
+
+    #[test]
+    fn applies_read_only_output_permissions() {
+        let fs = InMemoryFileSystem::new();
+        fs.insert("src/lib.in", "hello");
+
+        perform_processing("src".as_ref(), "in", &UppercaseProcessor, &fs, &IgnoreMatcher::new(), &OutputPermissions::ReadOnly, false).ok().expect("processing failed");
+
+        assert!(fs.permissions("src/lib.rs".as_ref()).unwrap().readonly());
+    }
+
+    #[test]
+    fn skips_up_to_date_outputs() {
+        let fs = InMemoryFileSystem::new();
+        let now = SystemTime::now();
+        fs.insert("src/lib.in", "hello");
+        fs.set_modified("src/lib.in".as_ref(), now);
+        fs.insert("src/lib.rs", "STALE");
+        fs.set_modified("src/lib.rs".as_ref(), now + Duration::from_secs(60));
+
+        assert!(is_up_to_date("src/lib.in".as_ref(), "src/lib.rs".as_ref(), &fs));
+
+        perform_processing("src".as_ref(), "in", &UppercaseProcessor, &fs, &IgnoreMatcher::new(), &OutputPermissions::Writable, true).ok().expect("processing failed");
+
+        assert_eq!(fs.contents("src/lib.rs".as_ref()).unwrap(), b"STALE");
+    }
+
+    #[test]
+    fn parallel_can_force_regeneration_of_up_to_date_outputs() {
+        let fs = InMemoryFileSystem::new();
+        let now = SystemTime::now();
+        fs.insert("src/lib.in", "hello");
+        fs.set_modified("src/lib.in".as_ref(), now);
+        fs.insert("src/lib.rs", "STALE");
+        fs.set_modified("src/lib.rs".as_ref(), now + Duration::from_secs(60));
+
+        perform_processing_parallel("src".as_ref(), "in", &UppercaseProcessor, &fs, &IgnoreMatcher::new(), &OutputPermissions::Writable, false).ok().expect("processing failed");
+
+        assert_eq!(fs.contents("src/lib.rs".as_ref()).unwrap(), b"HELLO");
+    }
+
+    #[test]
+    fn io_error_display_includes_operation_and_path() {
+        let source = ::std::io::Error::new(::std::io::ErrorKind::NotFound, "nope");
+        let error = io_error("reading", "src/lib.in".as_ref(), source);
+        match error {
+            Error::Io(io_error) => {
+                assert_eq!(format!("{}", io_error), "error reading src/lib.in: nope");
+            },
+            Error::Source(..) => panic!("expected Error::Io"),
+        }
+    }
+
+    #[test]
+    fn parallel_processing_produces_same_output_as_sequential() {
+        let fs = InMemoryFileSystem::new();
+        for i in 0..8 {
+            fs.insert(format!("src/file{}.in", i), "hello");
+        }
+
+        perform_processing_parallel("src".as_ref(), "in", &UppercaseProcessor, &fs, &IgnoreMatcher::new(), &OutputPermissions::Writable, false).ok().expect("processing failed");
+
+        for i in 0..8 {
+            assert_eq!(fs.contents(format!("src/file{}.rs", i).as_ref()).unwrap(), b"HELLO");
+        }
+    }
+
+    #[test]
+    fn apply_output_permissions_mode_sets_unix_mode() {
+        let fs = InMemoryFileSystem::new();
+        fs.insert("src/lib.rs", "HELLO");
+
+        apply_output_permissions("src/lib.rs".as_ref(), &fs, &OutputPermissions::Mode(0o644)).ok().expect("setting permissions failed");
+
+        let permissions = fs.permissions("src/lib.rs".as_ref()).unwrap();
+        if cfg!(unix) {
+            assert_eq!(permissions.mode(), Some(0o644));
+            assert!(!permissions.readonly());
+        } else {
+            assert!(permissions.readonly());
+        }
+    }
+
+    #[test]
+    fn mode_without_owner_write_bit_reports_readonly() {
+        let fs = InMemoryFileSystem::new();
+        fs.insert("src/lib.rs", "HELLO");
+
+        apply_output_permissions("src/lib.rs".as_ref(), &fs, &OutputPermissions::Mode(0o444)).ok().expect("setting permissions failed");
+
+        let permissions = fs.permissions("src/lib.rs".as_ref()).unwrap();
+        if cfg!(unix) {
+            assert!(permissions.readonly());
+        } else {
+            assert!(permissions.readonly());
+        }
+    }
+
+    #[test]
+    fn in_memory_file_system_read_dir_lists_direct_children_only() {
+        let fs = InMemoryFileSystem::new();
+        fs.insert("root/a.txt", "a");
+        fs.insert("root/sub/b.txt", "b");
+
+        let mut entries: Vec<_> = fs.read_dir("root".as_ref()).unwrap().into_iter().map(|e| (e.path, e.is_dir)).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(entries, vec![
+            (PathBuf::from("root/a.txt"), false),
+            (PathBuf::from("root/sub"), true),
+        ]);
+    }
+
+    #[test]
+    fn in_memory_file_system_create_then_remove_file() {
+        let fs = InMemoryFileSystem::new();
+        {
+            let mut file = fs.create("out.txt".as_ref()).unwrap();
+            file.write_all(b"generated").unwrap();
+        }
+        assert_eq!(fs.contents("out.txt".as_ref()).unwrap(), b"generated");
+
+        fs.remove_file("out.txt".as_ref()).unwrap();
+        assert!(fs.contents("out.txt".as_ref()).is_none());
+        assert!(fs.remove_file("out.txt".as_ref()).is_err());
+    }
+
+    #[test]
+    fn std_file_system_round_trips_through_real_disk() {
+        let path = ::std::env::temp_dir().join("build_compile_chunk0_1_roundtrip.txt");
+        let fs = StdFileSystem;
+
+        {
+            let mut file = fs.create(&path).unwrap();
+            file.write_all(b"hello").unwrap();
+        }
+
+        assert_eq!(fs.read_to_string(&path).unwrap(), "hello");
+        assert!(!fs.metadata(&path).unwrap().permissions().readonly());
+
+        let mut permissions = fs.metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs.set_permissions(&path, permissions).unwrap();
+        assert!(fs.metadata(&path).unwrap().permissions().readonly());
+
+        let mut permissions = fs.metadata(&path).unwrap().permissions();
+        permissions.set_readonly(false);
+        fs.set_permissions(&path, permissions).unwrap();
+
+        fs.remove_file(&path).unwrap();
+        assert!(fs.metadata(&path).is_err());
+    }
+}